@@ -1,31 +1,72 @@
 use std::collections::HashMap;
+use std::env;
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::Mutex;
 
 use core::{Package, Target, PackageId, PackageSet, Profile};
 use util::{CargoResult, human, Human};
 use util::{internal, ChainError, profile};
-use util::Freshness;
+use util::{Freshness, ProcessBuilder};
 
 use super::job::Work;
 use super::{fingerprint, process, Kind, Context, Platform};
 use super::CommandType;
 use super::PackagesToBuild;
 
+/// The kind of library that a `rustc-link-lib` directive links against, as
+/// specified by the optional `KIND=` prefix (e.g. `cargo:rustc-link-lib=static=foo`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkKind {
+    Static,
+    Dylib,
+    Framework,
+}
+
+impl LinkKind {
+    fn parse(kind: &str) -> Option<LinkKind> {
+        match kind {
+            "static" => Some(LinkKind::Static),
+            "dylib" => Some(LinkKind::Dylib),
+            "framework" => Some(LinkKind::Framework),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            LinkKind::Static => "static",
+            LinkKind::Dylib => "dylib",
+            LinkKind::Framework => "framework",
+        }
+    }
+}
+
 /// Contains the parsed output of a custom build script.
 #[derive(Clone, Debug)]
 pub struct BuildOutput {
     /// Paths to pass to rustc with the `-L` flag
     pub library_paths: Vec<PathBuf>,
     /// Names and link kinds of libraries, suitable for the `-l` flag
-    pub library_links: Vec<String>,
+    pub library_links: Vec<(Option<LinkKind>, String)>,
     /// Various `--cfg` flags to pass to the compiler
     pub cfgs: Vec<String>,
     /// Metadata to pass to the immediate dependencies
     pub metadata: Vec<(String, String)>,
+    /// Environment variables to set for the consuming crate's own compilation
+    pub env: Vec<(String, String)>,
+    /// Paths to trigger a rerun of this build script.
+    /// A rerun is also triggered if the build script itself changes.
+    pub rerun_if_changed: Vec<PathBuf>,
+    /// Environment variables which, when changed, will trigger a rerun of
+    /// this build script.
+    pub rerun_if_env_changed: Vec<String>,
+    /// Warnings printed by the build script via `cargo:warning=`. These are
+    /// printed through the shell after the build script runs, and again
+    /// whenever its cached output is replayed because the script is fresh.
+    pub warnings: Vec<String>,
 }
 
 pub type BuildMap = HashMap<(PackageId, Kind), BuildOutput>;
@@ -102,9 +143,12 @@ pub fn prepare(pkg: &Package, target: &Target, req: Platform,
     };
     let pkg_name = pkg.to_string();
     let build_state = cx.build_state.clone();
+    let config = cx.config.clone();
     let id = pkg.package_id().clone();
     let all = (id.clone(), pkg_name.clone(), build_state.clone(),
-               build_output.clone());
+               build_output.clone(), config.clone());
+    let cached_output_loc = build_output.clone();
+    let pkg_root = pkg.root().to_path_buf();
     let plugin_deps = super::load_build_deps(cx, pkg, target, profile,
                                              Kind::Host);
 
@@ -149,8 +193,16 @@ pub fn prepare(pkg: &Package, target: &Target, req: Platform,
         // And now finally, run the build command itself!
         desc_tx.send(p.to_string()).ok();
         let output = try!(exec_engine.exec_with_output(p).map_err(|mut e| {
-            e.desc = format!("failed to run custom build command for `{}`\n{}",
-                             pkg_name, e.desc);
+            let stderr = e.output.as_ref().map(|o| {
+                String::from_utf8_lossy(&o.stderr).into_owned()
+            });
+            e.desc = format!("failed to run custom build command for `{}`\n{}{}",
+                             pkg_name, e.desc, match stderr {
+                                 Some(ref s) if !s.is_empty() => {
+                                     format!("\n--- stderr\n{}", s)
+                                 }
+                                 _ => String::new(),
+                             });
             Human(e)
         }));
 
@@ -165,6 +217,24 @@ pub fn prepare(pkg: &Package, target: &Target, req: Platform,
             human("build script output was not valid utf-8")
         }));
         let parsed_output = try!(BuildOutput::parse(output, &pkg_name));
+        for warning in parsed_output.warnings.iter() {
+            let _ = config.shell().warn(warning);
+        }
+
+        // Snapshot the current value of any declared `rerun-if-env-changed`
+        // variables so that the next run can tell whether they changed.
+        if !parsed_output.rerun_if_env_changed.is_empty() {
+            let snapshot = parsed_output.rerun_if_env_changed.iter().map(|name| {
+                format!("{}={}\n", name, env::var(name).unwrap_or(String::new()))
+            }).collect::<String>();
+            try!(File::create(&build_output.parent().unwrap().join("output.env"))
+                      .and_then(|mut f| f.write_all(snapshot.as_bytes()))
+                      .map_err(|e| {
+                human(format!("failed to write cached build command env \
+                              snapshot: {}", e))
+            }));
+        }
+
         build_state.insert(id, req, parsed_output);
 
         try!(File::create(&build_output.parent().unwrap().join("output"))
@@ -183,17 +253,31 @@ pub fn prepare(pkg: &Package, target: &Target, req: Platform,
     //
     // Note that the freshness calculation here is the build_cmd freshness, not
     // target specific freshness. This is because we don't actually know what
-    // the inputs are to this command!
+    // the inputs are to this command ourselves, by default, so we ask
+    // `prepare_build_cmd` to just conservatively treat the whole package
+    // directory as the input set.
     //
-    // Also note that a fresh build command needs to
-    let (freshness, dirty, fresh) =
+    // If, however, the *previous* run of this build script declared precise
+    // `rerun-if-changed`/`rerun-if-env-changed` inputs, we trust those
+    // instead: the command is dirty only if one of the declared paths is
+    // newer than the last recorded run or one of the declared env vars
+    // changed value, which lets build scripts opt out of the whole-directory
+    // fallback.
+    let (package_freshness, dirty, fresh) =
             try!(fingerprint::prepare_build_cmd(cx, pkg, kind));
+    // When the previous run declared `rerun-if-*` inputs, their freshness is
+    // used as is; only when it declared none does the whole-package
+    // `package_freshness` computed above decide.
+    let freshness = match try!(rerun_freshness(&cached_output_loc, &pkg_name, &pkg_root)) {
+        Some(freshness) => freshness,
+        None => package_freshness,
+    };
     let dirty = Work::new(move |tx| {
         try!(work.call((tx.clone())));
         dirty.call(tx)
     });
     let fresh = Work::new(move |tx| {
-        let (id, pkg_name, build_state, build_output) = all;
+        let (id, pkg_name, build_state, build_output, config) = all;
         let new_loc = build_output.parent().unwrap().join("output");
         let mut f = try!(File::open(&new_loc).map_err(|e| {
             human(format!("failed to read cached build command output: {}", e))
@@ -201,6 +285,9 @@ pub fn prepare(pkg: &Package, target: &Target, req: Platform,
         let mut contents = String::new();
         try!(f.read_to_string(&mut contents));
         let output = try!(BuildOutput::parse(&contents, &pkg_name));
+        for warning in output.warnings.iter() {
+            let _ = config.shell().warn(warning);
+        }
         build_state.insert(id, req, output);
 
         fresh.call(tx)
@@ -209,6 +296,81 @@ pub fn prepare(pkg: &Package, target: &Target, req: Platform,
     Ok((dirty, fresh, freshness))
 }
 
+/// Consults the previous run's cached build script output (if any) for
+/// `rerun-if-changed`/`rerun-if-env-changed` declarations, and computes a
+/// precise `Freshness` from the declared paths' mtimes and env vars' current
+/// values. Returns `None` when there is no previous run, or the previous run
+/// declared no such directives, signaling that the caller should fall back
+/// to the conservative whole-package freshness check.
+fn rerun_freshness(build_output: &PathBuf, pkg_name: &str, pkg_root: &Path)
+                   -> CargoResult<Option<Freshness>> {
+    let cached_output = build_output.parent().unwrap().join("output");
+    let mut contents = String::new();
+    match File::open(&cached_output).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(..) => {}
+        Err(..) => return Ok(None),
+    }
+    let prev = try!(BuildOutput::parse(&contents, pkg_name));
+    if prev.rerun_if_changed.is_empty() && prev.rerun_if_env_changed.is_empty() {
+        return Ok(None)
+    }
+
+    let stamp_mtime = try!(fs::metadata(&cached_output).and_then(|m| m.modified()).map_err(|e| {
+        human(format!("failed to stat cached build command output: {}", e))
+    }));
+
+    for path in prev.rerun_if_changed.iter() {
+        // Declared paths are relative to the package that declared them, not
+        // to whatever directory cargo happens to be running from.
+        let path = if path.is_relative() {
+            pkg_root.join(path)
+        } else {
+            path.clone()
+        };
+        let mtime = match fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(mtime) => mtime,
+            // A declared path that's missing or unreadable is treated as
+            // changed, same as rustc does with its own dep-info.
+            Err(..) => return Ok(Some(Freshness::Dirty)),
+        };
+        if mtime > stamp_mtime {
+            return Ok(Some(Freshness::Dirty))
+        }
+    }
+
+    if !prev.rerun_if_env_changed.is_empty() {
+        let snapshot_loc = build_output.parent().unwrap().join("output.env");
+        let mut snapshot = String::new();
+        let read = File::open(&snapshot_loc)
+                        .and_then(|mut f| f.read_to_string(&mut snapshot));
+        let mut recorded = HashMap::new();
+        if read.is_ok() {
+            for line in snapshot.lines() {
+                let mut parts = line.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(k), Some(v)) => { recorded.insert(k.to_string(), v.to_string()); }
+                    _ => {}
+                }
+            }
+        }
+        for var in prev.rerun_if_env_changed.iter() {
+            let current = match env::var(var) {
+                Ok(v) => v,
+                Err(..) => String::new(),
+            };
+            let previous = match recorded.get(var) {
+                Some(v) => v.clone(),
+                None => String::new(),
+            };
+            if current != previous {
+                return Ok(Some(Freshness::Dirty))
+            }
+        }
+    }
+
+    Ok(Some(Freshness::Fresh))
+}
+
 impl BuildState {
     pub fn new(config: &super::BuildConfig,
                packages: &PackageSet) -> BuildState {
@@ -260,6 +422,10 @@ impl BuildOutput {
         let mut library_links = Vec::new();
         let mut cfgs = Vec::new();
         let mut metadata = Vec::new();
+        let mut env = Vec::new();
+        let mut rerun_if_changed = Vec::new();
+        let mut rerun_if_env_changed = Vec::new();
+        let mut warnings = Vec::new();
         let whence = format!("build script of `{}`", pkg_name);
 
         for line in input.lines() {
@@ -292,9 +458,25 @@ impl BuildOutput {
                     library_links.extend(links.into_iter());
                     library_paths.extend(libs.into_iter());
                 }
-                "rustc-link-lib" => library_links.push(value.to_string()),
+                "rustc-link-lib" => {
+                    library_links.push(try!(BuildOutput::parse_link_lib(value, &whence)));
+                }
                 "rustc-link-search" => library_paths.push(PathBuf::from(value)),
                 "rustc-cfg" => cfgs.push(value.to_string()),
+                "rustc-env" => {
+                    let mut iter = value.splitn(2, '=');
+                    let key = iter.next();
+                    let val = iter.next();
+                    match (key, val) {
+                        (Some(a), Some(b)) => env.push((a.to_string(), b.to_string())),
+                        _ => return Err(human(format!("Variable rustc-env has no \
+                                                       value in {}: `{}`",
+                                                       whence, value))),
+                    }
+                }
+                "rerun-if-changed" => rerun_if_changed.push(PathBuf::from(value)),
+                "rerun-if-env-changed" => rerun_if_env_changed.push(value.to_string()),
+                "warning" => warnings.push(value.to_string()),
                 _ => metadata.push((key.to_string(), value.to_string())),
             }
         }
@@ -304,11 +486,34 @@ impl BuildOutput {
             library_links: library_links,
             cfgs: cfgs,
             metadata: metadata,
+            env: env,
+            rerun_if_changed: rerun_if_changed,
+            rerun_if_env_changed: rerun_if_env_changed,
+            warnings: warnings,
         })
     }
 
+    /// Parses the `KIND=NAME` (or bare `NAME`) spelling used by both
+    /// `cargo:rustc-link-lib=` and the `-l` flag in `cargo:rustc-flags=`,
+    /// where `KIND` is one of `static`, `dylib`, or `framework`.
+    fn parse_link_lib(value: &str, whence: &str)
+                      -> CargoResult<(Option<LinkKind>, String)> {
+        let mut parts = value.splitn(2, '=');
+        let first = parts.next().unwrap();
+        match parts.next() {
+            Some(name) => {
+                match LinkKind::parse(first) {
+                    Some(kind) => Ok((Some(kind), name.to_string())),
+                    None => Err(human(format!("Unsupported link kind `{}` in {}: `{}`",
+                                              first, whence, value))),
+                }
+            }
+            None => Ok((None, first.to_string())),
+        }
+    }
+
     pub fn parse_rustc_flags(value: &str, whence: &str)
-                             -> CargoResult<(Vec<PathBuf>, Vec<String>)> {
+                             -> CargoResult<(Vec<PathBuf>, Vec<(Option<LinkKind>, String)>)> {
         let value = value.trim();
         let mut flags_iter = value.split(|c: char| c.is_whitespace())
                                   .filter(|w| w.chars().any(|c| !c.is_whitespace()));
@@ -330,7 +535,7 @@ impl BuildOutput {
                                                   whence, value)))
             };
             match flag {
-                "-l" => library_links.push(value.to_string()),
+                "-l" => library_links.push(try!(BuildOutput::parse_link_lib(value, whence))),
                 "-L" => library_paths.push(PathBuf::from(value)),
 
                 // was already checked above
@@ -339,6 +544,33 @@ impl BuildOutput {
         }
         Ok((library_paths, library_links))
     }
+
+    /// Applies the `rustc-env` variables declared by this build script to
+    /// the given process, so that `env!("KEY")` resolves them when the
+    /// package's own targets are compiled. This mirrors how
+    /// `library_paths`/`library_links` already flow from the build state
+    /// into the rustc invocation's `-L`/`-l` flags; the caller is expected
+    /// to invoke this alongside that existing flow for the package whose
+    /// build script produced this output.
+    pub fn apply_env(&self, cmd: &mut ProcessBuilder) {
+        for &(ref key, ref value) in self.env.iter() {
+            cmd.env(key, value);
+        }
+    }
+
+    /// Formats `library_links` the way rustc's `-l` flag expects: `KIND=NAME`
+    /// for a declared link kind, or a bare `NAME` for the default kind. The
+    /// caller passes each returned string as the value of a `-l` flag on the
+    /// rustc invocation, the same way `library_paths` already becomes `-L`
+    /// flags.
+    pub fn rustc_link_args(&self) -> Vec<String> {
+        self.library_links.iter().map(|&(ref kind, ref name)| {
+            match *kind {
+                Some(ref kind) => format!("{}={}", kind.as_str(), name),
+                None => name.clone(),
+            }
+        }).collect()
+    }
 }
 
 /// Compute the `build_scripts` map in the `Context` which tracks what build
@@ -426,3 +658,167 @@ pub fn build_map<'b, 'cfg>(cx: &mut Context<'b, 'cfg>,
         return prev
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::{BuildOutput, LinkKind, rerun_freshness};
+    use util::Freshness;
+
+    fn parse(input: &str) -> BuildOutput {
+        BuildOutput::parse(input, "foo").unwrap()
+    }
+
+    #[test]
+    fn link_lib_bare() {
+        let output = parse("cargo:rustc-link-lib=foo\n");
+        assert_eq!(output.library_links, vec![(None, "foo".to_string())]);
+        assert_eq!(output.rustc_link_args(), vec!["foo".to_string()]);
+    }
+
+    #[test]
+    fn link_lib_with_kind() {
+        let output = parse("cargo:rustc-link-lib=static=foo\n\
+                             cargo:rustc-link-lib=dylib=bar\n\
+                             cargo:rustc-link-lib=framework=baz\n");
+        assert_eq!(output.library_links, vec![
+            (Some(LinkKind::Static), "foo".to_string()),
+            (Some(LinkKind::Dylib), "bar".to_string()),
+            (Some(LinkKind::Framework), "baz".to_string()),
+        ]);
+        assert_eq!(output.rustc_link_args(), vec![
+            "static=foo".to_string(),
+            "dylib=bar".to_string(),
+            "framework=baz".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn link_lib_bad_kind_is_an_error() {
+        let err = BuildOutput::parse("cargo:rustc-link-lib=bogus=foo\n", "foo").err();
+        assert!(err.is_some());
+    }
+
+    #[test]
+    fn rustc_env_valid() {
+        let output = parse("cargo:rustc-env=FOO=bar\n");
+        assert_eq!(output.env, vec![("FOO".to_string(), "bar".to_string())]);
+    }
+
+    #[test]
+    fn rustc_env_without_equals_is_an_error() {
+        let err = BuildOutput::parse("cargo:rustc-env=FOO\n", "foo").err();
+        assert!(err.is_some());
+    }
+
+    #[test]
+    fn rerun_if_directives_are_collected() {
+        let output = parse("cargo:rerun-if-changed=build.rs\n\
+                             cargo:rerun-if-changed=src/lib.rs\n\
+                             cargo:rerun-if-env-changed=FOO\n");
+        assert_eq!(output.rerun_if_changed, vec![
+            PathBuf::from("build.rs"),
+            PathBuf::from("src/lib.rs"),
+        ]);
+        assert_eq!(output.rerun_if_env_changed, vec!["FOO".to_string()]);
+    }
+
+    #[test]
+    fn warning_is_captured() {
+        let output = parse("cargo:warning=oh no\n");
+        assert_eq!(output.warnings, vec!["oh no".to_string()]);
+    }
+
+    #[test]
+    fn unknown_key_falls_back_to_metadata() {
+        let output = parse("cargo:color=blue\n");
+        assert_eq!(output.metadata, vec![("color".to_string(), "blue".to_string())]);
+    }
+
+    // Lays out a fake "previous run" of a build script under a fresh temp
+    // directory: an `output` file holding the `cargo:` directive stream, and
+    // (optionally) an `output.env` snapshot alongside it, exactly as `work`
+    // in `prepare` above writes them.
+    struct Fixture {
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let dir = env::temp_dir().join(format!("cargo-custom-build-test-{}", name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Fixture { dir: dir }
+        }
+
+        fn write_output(&self, contents: &str) -> PathBuf {
+            let loc = self.dir.join("output");
+            File::create(&loc).unwrap().write_all(contents.as_bytes()).unwrap();
+            loc
+        }
+
+        fn write_env_snapshot(&self, contents: &str) {
+            let loc = self.dir.join("output.env");
+            File::create(&loc).unwrap().write_all(contents.as_bytes()).unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn rerun_freshness_is_none_without_declared_inputs() {
+        let fixture = Fixture::new("none");
+        let build_output = fixture.write_output("cargo:rustc-cfg=foo\n");
+        let result = rerun_freshness(&build_output, "foo", &fixture.dir).unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn rerun_freshness_dirty_when_declared_path_is_newer() {
+        let fixture = Fixture::new("dirty-path");
+        let build_output = fixture.write_output("cargo:rerun-if-changed=changed.txt\n");
+        let watched = fixture.dir.join("changed.txt");
+        File::create(&watched).unwrap().write_all(b"new").unwrap();
+
+        let result = rerun_freshness(&build_output, "foo", &fixture.dir).unwrap();
+        assert_eq!(result, Some(Freshness::Dirty));
+    }
+
+    #[test]
+    fn rerun_freshness_resolves_relative_paths_against_pkg_root() {
+        // The watched file only exists under `fixture.dir`, not the process's
+        // actual CWD, so this only comes out fresh if the relative path is
+        // resolved against the package root we passed in.
+        let fixture = Fixture::new("relative-root");
+        let watched = fixture.dir.join("src").join("lib.rs");
+        fs::create_dir_all(watched.parent().unwrap()).unwrap();
+        File::create(&watched).unwrap().write_all(b"old").unwrap();
+
+        let build_output = fixture.write_output("cargo:rerun-if-changed=src/lib.rs\n");
+
+        let result = rerun_freshness(&build_output, "foo", &fixture.dir).unwrap();
+        assert_eq!(result, Some(Freshness::Fresh));
+    }
+
+    #[test]
+    fn rerun_freshness_dirty_when_env_var_changed() {
+        let fixture = Fixture::new("dirty-env");
+        let build_output = fixture.write_output(
+            "cargo:rerun-if-env-changed=CARGO_CUSTOM_BUILD_TEST_VAR\n");
+        fixture.write_env_snapshot("CARGO_CUSTOM_BUILD_TEST_VAR=old\n");
+        env::set_var("CARGO_CUSTOM_BUILD_TEST_VAR", "new");
+
+        let result = rerun_freshness(&build_output, "foo", &fixture.dir).unwrap();
+        assert_eq!(result, Some(Freshness::Dirty));
+
+        env::remove_var("CARGO_CUSTOM_BUILD_TEST_VAR");
+    }
+}